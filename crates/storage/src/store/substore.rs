@@ -1,12 +1,13 @@
 use std::{
+    collections::BTreeMap,
     fmt::{Display, Formatter},
     sync::Arc,
 };
 
 use anyhow::Result;
-use borsh::BorshDeserialize;
+use borsh::{BorshDeserialize, BorshSerialize};
 use jmt::{
-    storage::{HasPreimage, LeafNode, Node, NodeKey, TreeReader},
+    storage::{Child, Children, HasPreimage, InternalNode, LeafNode, Node, NodeKey, TreeReader},
     KeyHash, RootHash,
 };
 use rocksdb::{ColumnFamily, IteratorMode, ReadOptions};
@@ -43,6 +44,19 @@ pub struct SubstoreConfig {
     /// part of consensus.
     /// maps: arbitrary keys to arbitrary values.
     cf_nonverifiable: String,
+    /// If set, values larger than this many bytes are not embedded directly in their
+    /// JMT leaf: the leaf instead commits to a `sha256` hash of the value, while the
+    /// full bytes continue to live in `cf_jmt_values` as usual. This shrinks `Node`
+    /// payloads in `cf_jmt` (and the proofs derived from them) for substores holding
+    /// large blobs. Changing this after a substore has committed versions does not
+    /// invalidate historical versions: the scheme in effect is recorded per-version, see
+    /// [`SubstoreSnapshot::get_with_proof`].
+    value_hashing_threshold: Option<usize>,
+    /// If set, the number of historical versions this substore is configured to keep on
+    /// top of the latest one. The value is only stored here: nothing reads it, no pruning
+    /// happens on its own, and [`SubstoreStorage::prune`] takes its watermark as an
+    /// explicit argument.
+    retention_depth: Option<u64>,
 }
 
 impl SubstoreConfig {
@@ -55,9 +69,26 @@ impl SubstoreConfig {
             cf_jmt_keys_by_keyhash: format!("substore-{}-jmt-keys-by-keyhash", prefix),
             cf_nonverifiable: format!("substore-{}-nonverifiable", prefix),
             prefix,
+            value_hashing_threshold: None,
+            retention_depth: None,
         }
     }
 
+    /// Enables inner value hashing for this substore: values larger than
+    /// `threshold_bytes` will have only their content hash folded into the JMT leaf.
+    pub fn with_value_hashing_threshold(mut self, threshold_bytes: usize) -> Self {
+        self.value_hashing_threshold = Some(threshold_bytes);
+        self
+    }
+
+    /// Sets this substore's retention depth to `depth` versions. This only records the
+    /// value on the config; it does not prune anything or bound how far back
+    /// `get_with_proof` can serve queries (see [`SubstoreStorage::prune`]).
+    pub fn with_retention_depth(mut self, depth: u64) -> Self {
+        self.retention_depth = Some(depth);
+        self
+    }
+
     /// Returns an iterator over all column families in this substore.
     /// This is verbose, but very lightweight.
     pub fn columns(&self) -> impl Iterator<Item = &String> {
@@ -68,6 +99,74 @@ impl SubstoreConfig {
             .chain(std::iter::once(&self.cf_nonverifiable))
     }
 
+    /// Enumerates the substore prefixes already present in an existing database at
+    /// `path`, by listing its column families and parsing the `substore-{prefix}-jmt`
+    /// naming scheme, so the storage layer can rediscover substores created in a
+    /// previous process instead of requiring the full set to be known up front.
+    ///
+    /// Errors if a discovered prefix has only some of its five column families, which
+    /// would indicate the database crashed mid-migration while registering a substore.
+    pub fn discover_prefixes(
+        opts: &rocksdb::Options,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Vec<String>> {
+        let cf_names = rocksdb::DB::list_cf(opts, path)?;
+
+        let mut prefixes: Vec<String> = cf_names
+            .iter()
+            .filter_map(|name| name.strip_prefix("substore-")?.strip_suffix("-jmt"))
+            .map(str::to_string)
+            .collect();
+        prefixes.sort();
+
+        for prefix in &prefixes {
+            let config = SubstoreConfig::new(prefix);
+            let missing: Vec<&String> = config
+                .columns()
+                .filter(|cf| !cf_names.contains(cf))
+                .collect();
+            anyhow::ensure!(
+                missing.is_empty(),
+                "substore '{}' is missing column families {:?}: the database may have \
+                 crashed while registering this substore",
+                prefix,
+                missing
+            );
+        }
+
+        Ok(prefixes)
+    }
+
+    /// Registers this substore on a live database handle by creating its five column
+    /// families on demand, so new logical stores can be added to a running node without
+    /// a full migration. A no-op if all five column families already exist.
+    ///
+    /// Errors if only some of the five already exist, to avoid leaving the substore in a
+    /// partially-initialized state that [`Self::discover_prefixes`] would later reject.
+    pub fn create_cf(&self, db: &rocksdb::DB, opts: &rocksdb::Options) -> Result<()> {
+        let existing: Vec<&String> = self
+            .columns()
+            .filter(|cf| db.cf_handle(cf).is_some())
+            .collect();
+
+        if existing.len() == self.columns().count() {
+            return Ok(());
+        }
+        anyhow::ensure!(
+            existing.is_empty(),
+            "substore '{}' has a partially-initialized set of column families: {:?} \
+             already exist",
+            self.prefix,
+            existing
+        );
+
+        for cf in self.columns() {
+            db.create_cf(cf, opts)?;
+        }
+
+        Ok(())
+    }
+
     pub fn cf_jmt<'s>(&self, db_handle: &'s Arc<rocksdb::DB>) -> &'s ColumnFamily {
         let column = self.cf_jmt.as_str();
         db_handle.cf_handle(column).expect(&format!(
@@ -114,6 +213,28 @@ impl SubstoreConfig {
             .map(|(node_key, _)| node_key.version()))
     }
 
+    /// Returns the oldest version this substore can currently serve `get_with_proof`
+    /// queries for, i.e. the low end of the retained history window. `None` means the
+    /// substore has never been pruned and can serve any version back to genesis.
+    ///
+    /// This is tracked explicitly (rather than derived from the oldest entry still
+    /// present in `cf_jmt`) because a pruned substore can still contain nodes from
+    /// before the watermark: unchanged subtrees keep the `NodeKey` of the version that
+    /// last wrote them, and [`SubstoreStorage::prune`] only ever removes entries that
+    /// are unreachable from the retained root.
+    pub fn earliest_version(&self, db_handle: Arc<rocksdb::DB>) -> Result<Option<jmt::Version>> {
+        let cf_nonverifiable = self.cf_nonverifiable(&db_handle);
+        Ok(db_handle
+            .get_cf(cf_nonverifiable, EARLIEST_VERSION_KEY)?
+            .map(|bytes| {
+                let bytes: [u8; 8] = bytes
+                    .as_slice()
+                    .try_into()
+                    .expect("earliest-version marker is always 8 bytes");
+                u64::from_be_bytes(bytes)
+            }))
+    }
+
     fn get_rightmost_leaf(
         &self,
         db_handle: Arc<rocksdb::DB>,
@@ -169,13 +290,103 @@ impl SubstoreSnapshot {
     /// Returns some value corresponding to the key, along with an ICS23 existence proof
     /// up to the current JMT root hash. If the key is not present, returns `None` and a
     /// non-existence proof.
+    ///
+    /// If inner value hashing was in effect when this key's leaf was *last written* and
+    /// its value exceeds the threshold recorded for that write, the JMT leaf commits to
+    /// `sha256(value)` rather than to `value` itself, so the emitted proof's leaf value
+    /// is rewritten to match what the tree actually committed to. Note this is the
+    /// write-version of the leaf, not `self.version()`: an unchanged key keeps whichever
+    /// leaf an earlier commit wrote, under whatever scheme was in effect back then, even
+    /// if the configured threshold has since changed.
     pub(crate) fn get_with_proof(
         &self,
         key: Vec<u8>,
     ) -> Result<(Option<Vec<u8>>, ics23::CommitmentProof)> {
         let version = self.version();
+        let key_hash = KeyHash::with::<sha2::Sha256>(&key);
         let tree = jmt::Sha256Jmt::new(self);
-        tree.get_with_ics23_proof(key, version)
+        let (value, mut proof) = tree.get_with_ics23_proof(key, version)?;
+
+        if let Some(value) = value.as_ref() {
+            let write_version = self
+                .value_write_version(version, key_hash)?
+                .expect("a value was returned, so it must have a write version");
+            if let Some(threshold) = self.value_hashing_threshold_at_version(write_version)? {
+                if value.len() > threshold {
+                    if let Some(ics23::commitment_proof::Proof::Exist(existence_proof)) =
+                        proof.proof.as_mut()
+                    {
+                        existence_proof.value = content_hash(value).to_vec();
+                    }
+                }
+            }
+        }
+
+        Ok((value, proof))
+    }
+
+    /// Returns the version at which the newest entry for `key_hash` at or before
+    /// `max_version` was actually written, mirroring the lookup performed by
+    /// [`TreeReader::get_value_option`] but returning the write-version instead of the
+    /// value itself.
+    fn value_write_version(
+        &self,
+        max_version: jmt::Version,
+        key_hash: KeyHash,
+    ) -> Result<Option<jmt::Version>> {
+        let cf_jmt_values = self.config.cf_jmt_values(&self.db);
+
+        if max_version == u64::MAX {
+            let k = VersionedKeyHash {
+                version: u64::MAX,
+                key_hash,
+            };
+            if self.rocksdb_snapshot.get_cf(cf_jmt_values, k.encode())?.is_some() {
+                return Ok(Some(u64::MAX));
+            }
+        }
+
+        let mut lower_bound = key_hash.0.to_vec();
+        lower_bound.extend_from_slice(&0u64.to_be_bytes());
+
+        let mut upper_bound = key_hash.0.to_vec();
+        upper_bound.extend_from_slice(&(max_version.saturating_add(1)).to_be_bytes());
+
+        let mut readopts = ReadOptions::default();
+        readopts.set_iterate_lower_bound(lower_bound);
+        readopts.set_iterate_upper_bound(upper_bound);
+        let mut iterator =
+            self.rocksdb_snapshot
+                .iterator_cf_opt(cf_jmt_values, readopts, IteratorMode::End);
+
+        let Some(tuple) = iterator.next() else {
+            return Ok(None);
+        };
+        let (key_bytes, _) = tuple?;
+        let version_bytes: [u8; 8] = key_bytes[key_bytes.len() - 8..]
+            .try_into()
+            .expect("versioned key hashes always end in an 8-byte version");
+        Ok(Some(u64::from_be_bytes(version_bytes)))
+    }
+
+    /// Returns the inner-value-hashing threshold that was in effect when `version` was
+    /// committed, or `None` if no threshold was configured for that commit (a version
+    /// committed with a threshold records it even if none of its values crossed it). This is recorded
+    /// per-version (rather than read from the live [`SubstoreConfig`]) so that historical
+    /// versions remain readable even after the configured threshold changes.
+    fn value_hashing_threshold_at_version(&self, version: jmt::Version) -> Result<Option<usize>> {
+        let cf_nonverifiable = self.config.cf_nonverifiable(&self.db);
+        let key = value_hashing_scheme_key(version);
+        Ok(self
+            .rocksdb_snapshot
+            .get_cf(cf_nonverifiable, key)?
+            .map(|bytes| {
+                let bytes: [u8; 8] = bytes
+                    .as_slice()
+                    .try_into()
+                    .expect("value-hashing scheme records are always 8 bytes");
+                u64::from_be_bytes(bytes) as usize
+            }))
     }
 
     /// Helper function used by `get_raw` and `prefix_raw`.
@@ -314,11 +525,20 @@ pub struct SubstoreStorage {
 }
 
 impl SubstoreStorage {
+    /// Commits `cache` as `new_version`, applying every mutation touched by the commit
+    /// (JMT nodes and values, the keyhash/preimage indices, and the nonverifiable
+    /// changes) through a single [`rocksdb::WriteBatch`], so a crash midway through a
+    /// commit can never leave the substore in a torn state where those column families
+    /// disagree: the version is either fully visible, or not at all.
+    ///
+    /// `sync_writes` controls whether the batch is flushed to disk (`WriteOptions::set_sync`)
+    /// before `commit` returns, trading latency for durability against an OS crash.
     pub async fn commit(
         self,
         cache: Cache,
         substore_snapshot: SubstoreSnapshot,
         new_version: jmt::Version,
+        sync_writes: bool,
     ) -> Result<RootHash> {
         let span = Span::current();
         let db_handle = self.db.clone();
@@ -328,6 +548,8 @@ impl SubstoreStorage {
                 .spawn_blocking(move || {
                     span.in_scope(|| {
                         let jmt = jmt::Sha256Jmt::new(&substore_snapshot);
+                        let mut batch = rocksdb::WriteBatch::default();
+                        let value_hashing_threshold = substore_snapshot.config.value_hashing_threshold;
 
                         // TODO(erwan): this could be folded with sharding the changesets.
                         let unwritten_changes: Vec<_> = cache
@@ -342,52 +564,91 @@ impl SubstoreStorage {
                         for (keyhash, key_preimage, value) in unwritten_changes.iter() {
                             match value {
                                 Some(_) => { /* Key inserted, or updated, so we add it to the keyhash index */
-                                    db_handle.put_cf(cf_jmt_keys, key_preimage, keyhash.0)?;
-                                        db_handle
-                                        .put_cf(cf_jmt_keys_by_keyhash, keyhash.0, key_preimage)?
+                                    batch.put_cf(cf_jmt_keys, key_preimage, keyhash.0);
+                                    batch.put_cf(cf_jmt_keys_by_keyhash, keyhash.0, key_preimage);
                                 }
                                 None => { /* Key deleted, so we delete it from the preimage and keyhash index entries */
-                                    db_handle.delete_cf(cf_jmt_keys, key_preimage)?;
-                                    db_handle.delete_cf(cf_jmt_keys_by_keyhash, keyhash.0)?;
+                                    batch.delete_cf(cf_jmt_keys, key_preimage);
+                                    batch.delete_cf(cf_jmt_keys_by_keyhash, keyhash.0);
                                 }
                             };
                         }
 
-                        let (root_hash, batch) = jmt.put_value_set(
-                            unwritten_changes.into_iter().map(|(keyhash, _key, some_value)| (keyhash, some_value)),
+                        // The raw values, keyed by keyhash, so that `cf_jmt_values` keeps
+                        // storing full bytes even for keys whose leaf commits to a content
+                        // hash instead (see `stage_node_batch`).
+                        let original_values: std::collections::HashMap<KeyHash, Option<Vec<u8>>> =
+                            unwritten_changes
+                                .iter()
+                                .map(|(keyhash, _key, value)| (*keyhash, value.clone()))
+                                .collect();
+
+                        let (root_hash, node_batch) = jmt.put_value_set(
+                            unwritten_changes.into_iter().map(|(keyhash, _key, some_value)| {
+                                let jmt_value = match (&some_value, value_hashing_threshold) {
+                                    (Some(v), Some(threshold)) if v.len() > threshold => {
+                                        Some(content_hash(v).to_vec())
+                                    }
+                                    _ => some_value,
+                                };
+                                (keyhash, jmt_value)
+                            }),
                             new_version,
                         )?;
 
-                        self.write_node_batch(&batch.node_batch)?;
-                        tracing::trace!(?root_hash, "wrote node batch to backing store");
+                        self.stage_node_batch(&mut batch, &node_batch.node_batch, &original_values)?;
+                        tracing::trace!(?root_hash, "staged node batch for commit");
+
+                        if let Some(threshold) = value_hashing_threshold {
+                            let cf_nonverifiable = substore_snapshot.config.cf_nonverifiable(&db_handle);
+                            batch.put_cf(
+                                cf_nonverifiable,
+                                value_hashing_scheme_key(new_version),
+                                (threshold as u64).to_be_bytes(),
+                            );
+                        }
 
                         for (k, v) in cache.nonverifiable_changes.into_iter() {
                             let cf_nonverifiable = substore_snapshot.config.cf_nonverifiable(&db_handle);
                             match v {
                                 Some(v) => {
                                     tracing::trace!(key = ?crate::EscapedByteSlice(&k), value = ?crate::EscapedByteSlice(&v), "put nonverifiable key");
-                                    db_handle.put_cf(cf_nonverifiable, k, &v)?;
+                                    batch.put_cf(cf_nonverifiable, k, &v);
                                 }
                                 None => {
-                                    db_handle.delete_cf(cf_nonverifiable, k)?;
+                                    batch.delete_cf(cf_nonverifiable, k);
                                 }
                             };
                         }
+
+                        let mut write_opts = rocksdb::WriteOptions::default();
+                        write_opts.set_sync(sync_writes);
+                        db_handle.write_opt(batch, &write_opts)?;
+
                         Ok(root_hash)
                     })
                 })?
                 .await?
     }
-}
 
-impl TreeWriter for SubstoreStorage {
-    /// Writes a [`NodeBatch`] into storage which includes the JMT
-    /// nodes (`DbNodeKey` -> `Node`) and the JMT values,
-    /// (`VersionedKeyHash` -> `Option<Vec<u8>>`).
-    fn write_node_batch(&self, node_batch: &jmt::storage::NodeBatch) -> Result<()> {
+    /// Stages a [`jmt::storage::NodeBatch`] into `batch`, without writing it: the JMT
+    /// nodes (`DbNodeKey` -> `Node`) and the JMT values (`VersionedKeyHash` ->
+    /// `Option<Vec<u8>>`). Staging rather than writing directly lets callers fold this
+    /// into a larger atomic batch alongside the other column families touched by a commit.
+    ///
+    /// `original_values` supplies the full, un-hashed bytes for any keyhash whose JMT
+    /// leaf commits to a content hash instead of its value (see `SubstoreConfig::
+    /// with_value_hashing_threshold`), so that `cf_jmt_values` keeps storing full values
+    /// regardless of what the tree itself committed to. Callers with nothing to
+    /// override (e.g. the bare [`TreeWriter`] impl) can pass an empty map.
+    fn stage_node_batch(
+        &self,
+        batch: &mut rocksdb::WriteBatch,
+        node_batch: &jmt::storage::NodeBatch,
+        original_values: &std::collections::HashMap<KeyHash, Option<Vec<u8>>>,
+    ) -> Result<()> {
         use borsh::BorshSerialize;
 
-        let node_batch = node_batch.clone();
         let cf_jmt = self.config.cf_jmt(&self.db);
 
         for (node_key, node) in node_batch.nodes() {
@@ -395,23 +656,44 @@ impl TreeWriter for SubstoreStorage {
             let db_node_key_bytes = db_node_key.encode()?;
             let value_bytes = &node.try_to_vec()?;
             tracing::trace!(?db_node_key_bytes, value_bytes = ?hex::encode(value_bytes));
-            self.db.put_cf(cf_jmt, db_node_key_bytes, value_bytes)?;
+            batch.put_cf(cf_jmt, db_node_key_bytes, value_bytes);
         }
         let cf_jmt_values = self.config.cf_jmt_values(&self.db);
 
         for ((version, key_hash), some_value) in node_batch.values() {
             let versioned_key = VersionedKeyHash::new(*version, *key_hash);
             let key_bytes = &versioned_key.encode();
-            let value_bytes = &some_value.try_to_vec()?;
+            let stored_value = original_values
+                .get(key_hash)
+                .cloned()
+                .unwrap_or_else(|| some_value.clone());
+            let value_bytes = &stored_value.try_to_vec()?;
             tracing::trace!(?key_bytes, value_bytes = ?hex::encode(value_bytes));
 
-            self.db.put_cf(cf_jmt_values, key_bytes, value_bytes)?;
+            batch.put_cf(cf_jmt_values, key_bytes, value_bytes);
         }
 
         Ok(())
     }
 }
 
+impl TreeWriter for SubstoreStorage {
+    /// Writes a [`NodeBatch`] into storage which includes the JMT
+    /// nodes (`DbNodeKey` -> `Node`) and the JMT values,
+    /// (`VersionedKeyHash` -> `Option<Vec<u8>>`).
+    ///
+    /// This writes the batch on its own, via a single [`rocksdb::WriteBatch`] covering
+    /// just `cf_jmt` and `cf_jmt_values`. Callers that need to fold this together with
+    /// other column families into one atomic commit (as [`SubstoreStorage::commit`]
+    /// does) should use [`SubstoreStorage::stage_node_batch`] instead.
+    fn write_node_batch(&self, node_batch: &jmt::storage::NodeBatch) -> Result<()> {
+        let mut batch = rocksdb::WriteBatch::default();
+        self.stage_node_batch(&mut batch, node_batch, &Default::default())?;
+        self.db.write(batch)?;
+        Ok(())
+    }
+}
+
 /// An ordered node key is a node key that is encoded in a way that
 /// preserves the order of the node keys in the database.
 pub struct DbNodeKey(NodeKey);
@@ -443,3 +725,911 @@ impl DbNodeKey {
         Ok(DbNodeKey(node_key))
     }
 }
+
+/// The key under which [`SubstoreRestore`] persists its in-progress frontier, so that an
+/// interrupted restore can resume instead of starting over. Lives in `cf_nonverifiable`
+/// since it isn't part of the tree being restored.
+const RESTORE_FRONTIER_KEY: &[u8] = b"jmt-restore-frontier";
+
+/// Prefix under which [`SubstoreStorage::commit`] records, for each version that used
+/// inner value hashing, the threshold that was in effect. Lives in `cf_nonverifiable`
+/// since it isn't part of the tree being committed.
+const VALUE_HASHING_SCHEME_PREFIX: &[u8] = b"jmt-value-hashing-threshold/";
+
+fn value_hashing_scheme_key(version: jmt::Version) -> Vec<u8> {
+    let mut key = VALUE_HASHING_SCHEME_PREFIX.to_vec();
+    key.extend_from_slice(&version.to_be_bytes());
+    key
+}
+
+/// The content hash folded into a JMT leaf in place of the raw value, once that value's
+/// size crosses a substore's configured `value_hashing_threshold`.
+fn content_hash(value: &[u8]) -> [u8; 32] {
+    use sha2::Digest as _;
+    let mut hasher = sha2::Sha256::new();
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+/// The key under which [`SubstoreStorage::prune`] records the oldest version still
+/// retained, read back by [`SubstoreConfig::earliest_version`]. Lives in
+/// `cf_nonverifiable` since it isn't part of the tree being pruned.
+const EARLIEST_VERSION_KEY: &[u8] = b"jmt-earliest-version";
+
+/// Bulk-deletes in chunks of this many keys per `WriteBatch`, so a prune over a large
+/// history window doesn't hold one unbounded batch in memory or block other writers for
+/// too long at once.
+const PRUNE_BATCH_SIZE: usize = 10_000;
+
+impl SubstoreStorage {
+    /// Prunes historical versions strictly older than `keep_at_least_version`: deletes
+    /// `cf_jmt` nodes and `cf_jmt_values` entries from those versions that are no longer
+    /// reachable from the `keep_at_least_version` root, while leaving that version (and
+    /// everything newer) fully servable by `get_with_proof`.
+    ///
+    /// This walks the `keep_at_least_version` root once up front to determine which
+    /// older-versioned nodes are still shared (JMT internal nodes keep the `NodeKey` of
+    /// the version that last wrote them, so an unchanged subtree's nodes can easily
+    /// predate the watermark). `cf_jmt` sorts by `DbNodeKey`'s version-prefixed ordering,
+    /// so the stale region is a single contiguous prefix of the column family: we
+    /// `delete_range_cf` it in one shot, then put back the (still-reachable) nodes the
+    /// walk found inside that range. `cf_jmt_values` sorts by key hash first, so its
+    /// stale entries aren't contiguous; those are deleted key-by-key in bounded
+    /// `WriteBatch`es, and only when superseded by a newer entry for the same key hash,
+    /// so a key whose value hasn't changed since before the watermark keeps the one
+    /// entry that still answers queries at or after it.
+    ///
+    /// The `VALUE_HASHING_SCHEME_PREFIX` markers `commit` records in `cf_nonverifiable`
+    /// are left in place, including those for versions below the watermark: a leaf still
+    /// reachable from the retained root can have been written before the watermark, and
+    /// `get_with_proof` reads the marker of the version that wrote it.
+    ///
+    /// Intended to be driven periodically from a background task; `keep_at_least_version`
+    /// is typically `latest_version - retention_depth`.
+    pub fn prune(&self, keep_at_least_version: jmt::Version) -> Result<()> {
+        let cf_jmt = self.config.cf_jmt(&self.db);
+        let cf_jmt_values = self.config.cf_jmt_values(&self.db);
+        let cf_nonverifiable = self.config.cf_nonverifiable(&self.db);
+
+        let retained_nodes = self.reachable_nodes(keep_at_least_version)?;
+
+        // Everything in `cf_jmt` sorts by BE(version) first, so the stale region is a
+        // single contiguous prefix of the column family.
+        let upper_bound = keep_at_least_version.to_be_bytes().to_vec();
+
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.delete_range_cf(cf_jmt, &[][..], upper_bound.as_slice());
+        for (node_key, bytes) in &retained_nodes {
+            let db_node_key = DbNodeKey::from(node_key.clone());
+            batch.put_cf(cf_jmt, db_node_key.encode()?, bytes);
+        }
+        self.db.write(batch)?;
+
+        // `cf_jmt_values` is keyed by `key_hash || BE(version)`, so entries for a given
+        // key are contiguous and ascending by version, but different keys' stale runs
+        // are scattered across the whole column family. Walk it once, and whenever a
+        // later sub-watermark entry for the same key hash arrives, the earlier one it
+        // supersedes can be deleted; the *last* sub-watermark entry for each key hash is
+        // left alone, since it's the one that answers queries at or after the watermark
+        // for a key that hasn't changed since.
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut pending = 0usize;
+        let mut superseded_candidate: Option<(Vec<u8>, Vec<u8>)> = None; // (key_hash, key)
+        let mut iter = self.db.raw_iterator_cf(cf_jmt_values);
+        iter.seek_to_first();
+        while iter.valid() {
+            let Some(key) = iter.key() else { break };
+            let key_hash = key[..key.len() - 8].to_vec();
+            let version_bytes = &key[key.len() - 8..];
+            let version = u64::from_be_bytes(version_bytes.try_into().expect("8 bytes"));
+
+            if superseded_candidate
+                .as_ref()
+                .is_some_and(|(pending_hash, _)| pending_hash != &key_hash)
+            {
+                // New key hash: whatever was pending for the previous one is the last
+                // sub-watermark entry for that key, and must be kept.
+                superseded_candidate = None;
+            }
+
+            if version < keep_at_least_version {
+                if let Some((_, stale_key)) = superseded_candidate.replace((key_hash, key.to_vec()))
+                {
+                    batch.delete_cf(cf_jmt_values, &stale_key);
+                    pending += 1;
+                    if pending >= PRUNE_BATCH_SIZE {
+                        self.db.write(std::mem::take(&mut batch))?;
+                        pending = 0;
+                    }
+                }
+            } else {
+                superseded_candidate = None;
+            }
+            iter.next();
+        }
+        if pending > 0 {
+            self.db.write(batch)?;
+        }
+
+        self.db.put_cf(
+            cf_nonverifiable,
+            EARLIEST_VERSION_KEY,
+            keep_at_least_version.to_be_bytes(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns every `(NodeKey, encoded bytes)` reachable from the `keep_at_least_version`
+    /// root that predates `keep_at_least_version` itself: those are the only nodes
+    /// `prune` ever needs to put back after range-deleting the stale region of `cf_jmt`.
+    /// The walk still visits every reachable node regardless of its own version (an
+    /// unchanged subtree can have old nodes beneath new ones, so a node's own recency
+    /// says nothing about its descendants'), it just doesn't hold on to the bytes of
+    /// ones that were never going to be deleted in the first place.
+    fn reachable_nodes(
+        &self,
+        keep_at_least_version: jmt::Version,
+    ) -> Result<std::collections::HashMap<NodeKey, Vec<u8>>> {
+        let mut visited = std::collections::HashSet::new();
+        let mut reachable = std::collections::HashMap::new();
+        let root_key = NodeKey::new(keep_at_least_version, jmt::storage::NibblePath::new(vec![]));
+        self.collect_reachable(&root_key, keep_at_least_version, &mut visited, &mut reachable)?;
+        Ok(reachable)
+    }
+
+    fn collect_reachable(
+        &self,
+        node_key: &NodeKey,
+        keep_at_least_version: jmt::Version,
+        visited: &mut std::collections::HashSet<NodeKey>,
+        reachable: &mut std::collections::HashMap<NodeKey, Vec<u8>>,
+    ) -> Result<()> {
+        if !visited.insert(node_key.clone()) {
+            // Already visited via another path (can happen once historical roots start
+            // sharing subtrees across multiple retained versions).
+            return Ok(());
+        }
+
+        let cf_jmt = self.config.cf_jmt(&self.db);
+        let db_node_key = DbNodeKey::from(node_key.clone());
+        let Some(bytes) = self.db.get_cf(cf_jmt, db_node_key.encode()?)? else {
+            // Already pruned, or this version never wrote a node at this path: nothing
+            // further to walk.
+            return Ok(());
+        };
+
+        let node = Node::try_from_slice(&bytes)?;
+        if let Node::Internal(internal_node) = &node {
+            for (nibble, child) in internal_node.children_sorted() {
+                let mut child_nibble_path = node_key.nibble_path().clone();
+                child_nibble_path.push(nibble);
+                let child_key = NodeKey::new(child.version, child_nibble_path);
+                self.collect_reachable(&child_key, keep_at_least_version, visited, reachable)?;
+            }
+        }
+
+        if node_key.version() < keep_at_least_version {
+            reachable.insert(node_key.clone(), bytes);
+        }
+
+        Ok(())
+    }
+}
+
+/// A node that has been fully reconstructed, but not yet linked into its parent because
+/// its siblings haven't arrived yet. Kept in memory until the leaf stream diverges away
+/// from its nibble path, at which point it is finalized and written out.
+#[derive(Clone, BorshSerialize, BorshDeserialize)]
+struct FrontierNode {
+    node_key: NodeKey,
+    node: Node,
+}
+
+/// The right frontier of a Jellyfish Merkle Tree under construction: for each nibble
+/// depth along the path to the most recently ingested leaf, the most recent node at that
+/// depth that is still waiting for a sibling before its parent can be finalized.
+#[derive(Clone, Default, BorshSerialize, BorshDeserialize)]
+struct RestoreFrontier {
+    /// `levels[d]` holds the pending node at nibble depth `d`, along with the other
+    /// children of its parent that have already been assigned.
+    levels: Vec<BTreeMap<u8, FrontierNode>>,
+    previous_key_hash: Option<KeyHash>,
+    num_leaves: u64,
+}
+
+/// Reconstructs a substore's Jellyfish Merkle Tree from an ordered stream of leaves,
+/// instead of replaying every historical version, so a new node can bootstrap from a
+/// snapshot in minutes rather than re-executing the chain.
+///
+/// Chunks must be ingested in strictly ascending [`KeyHash`] order. Each chunk is
+/// verified against the target root hash before anything is written, so a malicious
+/// snapshot provider cannot poison the store. Completed subtrees on the right frontier
+/// are written straight to `cf_jmt`/`cf_jmt_values` by the restore itself (not through
+/// [`TreeWriter`]) as soon as they are known to be final, so only the partial nodes along the frontier are kept
+/// in memory. The frontier is persisted to `cf_nonverifiable` after every chunk, so an
+/// interrupted restore can resume where it left off.
+pub struct SubstoreRestore {
+    db: Arc<rocksdb::DB>,
+    config: Arc<SubstoreConfig>,
+    target_root_hash: RootHash,
+    target_version: jmt::Version,
+    frontier: RestoreFrontier,
+}
+
+impl SubstoreConfig {
+    /// Begins (or resumes) a streaming restore of this substore's JMT, targeting
+    /// `target_version` and expecting the final root hash to equal `target_root_hash`.
+    ///
+    /// If a restore was previously interrupted, its frontier is loaded from
+    /// `cf_nonverifiable` and ingestion continues from the last persisted leaf.
+    pub fn restore(
+        self: Arc<Self>,
+        db: Arc<rocksdb::DB>,
+        target_root_hash: RootHash,
+        target_version: jmt::Version,
+    ) -> Result<SubstoreRestore> {
+        let cf_nonverifiable = self.cf_nonverifiable(&db);
+        let frontier = db
+            .get_cf(cf_nonverifiable, RESTORE_FRONTIER_KEY)?
+            .map(|bytes| RestoreFrontier::try_from_slice(&bytes))
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(SubstoreRestore {
+            db,
+            config: self,
+            target_root_hash,
+            target_version,
+            frontier,
+        })
+    }
+}
+
+impl SubstoreRestore {
+    /// The number of leaves ingested by this restore so far, across all chunks.
+    pub fn num_leaves(&self) -> u64 {
+        self.frontier.num_leaves
+    }
+
+    /// Ingests a chunk of leaves, which must be ordered by strictly ascending
+    /// [`KeyHash`] and must not have been seen in a previous chunk, and whose
+    /// inclusion in `target_root_hash` is attested to by `proof`.
+    ///
+    /// Nothing is persisted until `proof` has been checked against every leaf in the
+    /// chunk, so a chunk that fails verification leaves the store untouched.
+    pub fn add_chunk(
+        &mut self,
+        leaves: Vec<(KeyHash, jmt::OwnedValue)>,
+        proof: ics23::CommitmentProof,
+    ) -> Result<()> {
+        if leaves.is_empty() {
+            return Ok(());
+        }
+
+        for window in leaves.windows(2) {
+            if window[0].0 >= window[1].0 {
+                anyhow::bail!("chunk contains out-of-order or duplicate key hashes");
+            }
+        }
+        if let Some(previous) = self.frontier.previous_key_hash {
+            if leaves[0].0 <= previous {
+                anyhow::bail!("chunk does not continue strictly after the previous chunk");
+            }
+        }
+
+        verify_chunk_proof(&self.target_root_hash, &leaves, &proof)?;
+
+        for (key_hash, value) in leaves {
+            self.ingest_leaf(key_hash, value)?;
+        }
+
+        let cf_nonverifiable = self.config.cf_nonverifiable(&self.db);
+        self.db.put_cf(
+            cf_nonverifiable,
+            RESTORE_FRONTIER_KEY,
+            self.frontier.try_to_vec()?,
+        )?;
+
+        Ok(())
+    }
+
+    /// Folds a single leaf into the frontier, writing out any ancestor node that can no
+    /// longer receive additional children now that the leaf stream has moved past it.
+    fn ingest_leaf(&mut self, key_hash: KeyHash, value: jmt::OwnedValue) -> Result<()> {
+        let nibble_path = nibbles_of(&key_hash);
+
+        // Everything at a depth deeper than where this leaf's path diverges from the
+        // previous one is now final: no future leaf (they only get larger) can land
+        // underneath it.
+        let common_depth = match self.frontier.previous_key_hash {
+            Some(previous) => common_nibble_prefix_len(&nibbles_of(&previous), &nibble_path),
+            None => 0,
+        };
+        self.finalize_levels_below(common_depth)?;
+
+        let leaf_node = Node::Leaf(LeafNode::new(key_hash, value));
+        let leaf_node_key = NodeKey::new(self.target_version, nibble_path.clone());
+
+        // Not written yet: this leaf may still pass through any number of singleton
+        // frontier levels before its final (and possibly much shallower) compressed
+        // depth is known, and writing it under the wrong key would leave an orphan
+        // that the restored tree's `InternalNode`s never actually point at. It is
+        // persisted once that depth is fixed, in `finalize_level`.
+        let mut child = FrontierNode {
+            node_key: leaf_node_key,
+            node: leaf_node,
+        };
+
+        // Walk back up from the leaf's own depth, merging with the other children
+        // already collected at each level, until we reach a level that still has room
+        // for more children (i.e. further nibble values not yet ruled out).
+        for depth in (common_depth..nibble_path.num_nibbles()).rev() {
+            let nibble = nibble_path.get(depth);
+            self.frontier.level_mut(depth).insert(nibble, child.clone());
+
+            if depth == common_depth {
+                break;
+            }
+
+            // This level's siblings cannot grow any further: the next leaf's path
+            // will diverge at or before `common_depth`, so freeze it into an internal
+            // node now and hand it up to its parent as `child`.
+            child = self.finalize_level(depth)?;
+        }
+
+        self.frontier.previous_key_hash = Some(key_hash);
+        self.frontier.num_leaves += 1;
+        Ok(())
+    }
+
+    /// Finalizes every frontier level strictly deeper than `depth`, writing the
+    /// resulting internal nodes to `cf_jmt` and linking each one into its parent level.
+    fn finalize_levels_below(&mut self, depth: usize) -> Result<()> {
+        for d in (depth + 1..self.frontier.levels.len()).rev() {
+            if self.frontier.level_mut(d).is_empty() {
+                continue;
+            }
+            let finalized = self.finalize_level(d)?;
+            if d == 0 {
+                break;
+            }
+            let parent_nibble = finalized.node_key.nibble_path().get(d - 1);
+            self.frontier
+                .level_mut(d - 1)
+                .insert(parent_nibble, finalized);
+        }
+        Ok(())
+    }
+
+    /// Combines every child collected at `depth` into a single internal node and
+    /// returns it, so the caller can link it into the parent level. If only one child
+    /// was ever collected at this depth, there is no branch to record: a Jellyfish
+    /// Merkle Tree compresses chains of single-child nodes, so the lone child is
+    /// passed up unchanged instead of being wrapped (and re-hashed) as a new node.
+    ///
+    /// A child only has a fixed disk address once it is known to be a genuine sibling
+    /// here: the singleton passthrough above means a node can bubble up through any
+    /// number of shallower levels, unwritten, before landing in a `children` map with
+    /// more than one entry. So each child is written out now, for the first time, at
+    /// `depth + 1` nibbles — one deeper than the internal node being built here, which
+    /// covers `depth` nibbles. The new internal node itself is left unwritten: it will
+    /// be persisted the same way, either as someone else's child or, if it's what's
+    /// left once the whole frontier folds down, as the restore's root (`finalize`
+    /// writes that one explicitly, since it has no parent to be a child of).
+    fn finalize_level(&mut self, depth: usize) -> Result<FrontierNode> {
+        let mut children = std::mem::take(self.frontier.level_mut(depth));
+        anyhow::ensure!(!children.is_empty(), "cannot finalize an empty level");
+
+        if children.len() == 1 {
+            let (_, only_child) = children.pop_first().expect("checked non-empty above");
+            return Ok(only_child);
+        }
+
+        let parent_nibble_path = {
+            let any_child = children.values().next().expect("checked non-empty above");
+            any_child.node_key.nibble_path().truncate(depth)
+        };
+
+        let mut jmt_children = Children::default();
+        for (nibble, frontier_node) in children {
+            let child_node_key = NodeKey::new(
+                self.target_version,
+                frontier_node.node_key.nibble_path().truncate(depth + 1),
+            );
+            self.write_node(&child_node_key, &frontier_node.node)?;
+
+            jmt_children.insert(
+                jmt::storage::Nibble::from(nibble),
+                Child::new(
+                    frontier_node.node.hash(),
+                    self.target_version,
+                    frontier_node.node.node_type(),
+                ),
+            );
+        }
+
+        let node_key = NodeKey::new(self.target_version, parent_nibble_path);
+        let node = Node::Internal(InternalNode::new(jmt_children));
+
+        Ok(FrontierNode { node_key, node })
+    }
+
+    fn write_node(&self, node_key: &NodeKey, node: &Node) -> Result<()> {
+        let db_node_key = DbNodeKey::from(node_key.clone());
+        let cf_jmt = self.config.cf_jmt(&self.db);
+        self.db
+            .put_cf(cf_jmt, db_node_key.encode()?, node.try_to_vec()?)?;
+
+        if let Node::Leaf(leaf) = node {
+            let cf_jmt_values = self.config.cf_jmt_values(&self.db);
+            let versioned_key = VersionedKeyHash::new(self.target_version, leaf.key_hash());
+            self.db.put_cf(
+                cf_jmt_values,
+                versioned_key.encode(),
+                leaf.value().try_to_vec()?,
+            )?;
+
+            let cf_jmt_keys_by_keyhash = self.config.cf_jmt_keys_by_keyhash(&self.db);
+            self.db
+                .put_cf(cf_jmt_keys_by_keyhash, leaf.key_hash().0, [])?;
+        }
+
+        Ok(())
+    }
+
+    /// Finishes the restore: folds whatever remains on the frontier up to the root and
+    /// checks that it matches `target_root_hash`, erroring if it does not.
+    pub fn finalize(mut self) -> Result<RootHash> {
+        self.finalize_levels_below(0)?;
+
+        // Whatever is left at level 0 is everything that was ever collected at the very
+        // top of the tree, keyed by each entry's first nibble (not necessarily nibble
+        // `0`): finalizing it folds it down to the single root node, with the same
+        // single-child passthrough `finalize_level` applies everywhere else (a substore
+        // with exactly one key has a leaf, not an internal node, as its root).
+        let root_hash = if self.frontier.level_mut(0).is_empty() {
+            // An empty tree hashes to the all-zero root, matching `SubstoreSnapshot::root_hash`.
+            RootHash([0; 32])
+        } else {
+            let root = self.finalize_level(0)?;
+            let root_node_key = NodeKey::new(self.target_version, jmt::storage::NibblePath::new(vec![]));
+            self.write_node(&root_node_key, &root.node)?;
+            RootHash(root.node.hash())
+        };
+
+        anyhow::ensure!(
+            root_hash == self.target_root_hash,
+            "restored root hash {:?} does not match target root hash {:?}",
+            root_hash,
+            self.target_root_hash
+        );
+
+        let cf_nonverifiable = self.config.cf_nonverifiable(&self.db);
+        self.db.delete_cf(cf_nonverifiable, RESTORE_FRONTIER_KEY)?;
+
+        Ok(root_hash)
+    }
+}
+
+impl RestoreFrontier {
+    fn level_mut(&mut self, depth: usize) -> &mut BTreeMap<u8, FrontierNode> {
+        if self.levels.len() <= depth {
+            self.levels.resize_with(depth + 1, BTreeMap::new);
+        }
+        &mut self.levels[depth]
+    }
+}
+
+fn nibbles_of(key_hash: &KeyHash) -> jmt::storage::NibblePath {
+    jmt::storage::NibblePath::new(key_hash.0.to_vec())
+}
+
+fn common_nibble_prefix_len(a: &jmt::storage::NibblePath, b: &jmt::storage::NibblePath) -> usize {
+    (0..a.num_nibbles())
+        .take_while(|&i| a.get(i) == b.get(i))
+        .count()
+}
+
+/// Verifies, before the caller persists anything from the chunk, that `proof` shows each
+/// of `leaves` to be included under `target_root_hash`. It does not check that the chunk
+/// holds every key committed in its key range.
+fn verify_chunk_proof(
+    target_root_hash: &RootHash,
+    leaves: &[(KeyHash, jmt::OwnedValue)],
+    proof: &ics23::CommitmentProof,
+) -> Result<()> {
+    for (key_hash, value) in leaves {
+        anyhow::ensure!(
+            ics23::verify_membership::<ics23::HostFunctionsManager>(
+                proof,
+                &ics23::jmt_spec(),
+                &target_root_hash.0.to_vec(),
+                &key_hash.0,
+                value,
+            ),
+            "range proof does not prove inclusion of key hash {:?} under target root",
+            key_hash,
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jmt::mock::MockTreeStore;
+
+    /// Opens a fresh on-disk substore with all five column families created, backed by a
+    /// temporary directory that is cleaned up when the returned guard is dropped.
+    fn open_test_substore(config: &SubstoreConfig) -> (tempfile::TempDir, Arc<rocksdb::DB>) {
+        let dir = tempfile::tempdir().expect("failed to create temp dir for test substore");
+
+        let mut db_opts = rocksdb::Options::default();
+        db_opts.create_if_missing(true);
+        db_opts.create_missing_column_families(true);
+        let cf_descriptors: Vec<_> = config
+            .columns()
+            .map(|name| rocksdb::ColumnFamilyDescriptor::new(name, rocksdb::Options::default()))
+            .collect();
+        let db = rocksdb::DB::open_cf_descriptors(&db_opts, dir.path(), cf_descriptors)
+            .expect("failed to open test database");
+
+        (dir, Arc::new(db))
+    }
+
+    /// A bare-bones [`TreeReader`] over a restored substore's on-disk `cf_jmt`/
+    /// `cf_jmt_values`, used only to walk the tree in tests. Unlike
+    /// [`SubstoreSnapshot`], it reads directly off the live `db` handle instead of a
+    /// `RocksDbSnapshot`, since tests have no use for point-in-time isolation.
+    struct TestTreeReader {
+        db: Arc<rocksdb::DB>,
+        config: Arc<SubstoreConfig>,
+    }
+
+    impl TreeReader for TestTreeReader {
+        fn get_node_option(&self, node_key: &NodeKey) -> Result<Option<Node>> {
+            let cf_jmt = self.config.cf_jmt(&self.db);
+            let db_node_key = DbNodeKey::from(node_key.clone());
+            self.db
+                .get_cf(cf_jmt, db_node_key.encode()?)?
+                .map(|bytes| Node::try_from_slice(&bytes))
+                .transpose()
+                .map_err(Into::into)
+        }
+
+        fn get_value_option(
+            &self,
+            max_version: jmt::Version,
+            key_hash: KeyHash,
+        ) -> Result<Option<jmt::OwnedValue>> {
+            let cf_jmt_values = self.config.cf_jmt_values(&self.db);
+            let mut lower_bound = key_hash.0.to_vec();
+            lower_bound.extend_from_slice(&0u64.to_be_bytes());
+            let mut upper_bound = key_hash.0.to_vec();
+            upper_bound.extend_from_slice(&(max_version.saturating_add(1)).to_be_bytes());
+
+            let mut readopts = ReadOptions::default();
+            readopts.set_iterate_lower_bound(lower_bound);
+            readopts.set_iterate_upper_bound(upper_bound);
+            let mut iterator =
+                self.db
+                    .iterator_cf_opt(cf_jmt_values, readopts, IteratorMode::End);
+
+            let Some(tuple) = iterator.next() else {
+                return Ok(None);
+            };
+            let (_key, v) = tuple?;
+            Ok(BorshDeserialize::try_from_slice(v.as_ref())?)
+        }
+
+        fn get_rightmost_leaf(&self) -> Result<Option<(NodeKey, LeafNode)>> {
+            let cf_jmt = self.config.cf_jmt(&self.db);
+            let mut iter = self.db.raw_iterator_cf(cf_jmt);
+            iter.seek_to_last();
+
+            if iter.valid() {
+                let node_key =
+                    DbNodeKey::decode(iter.key().expect("all DB entries should have a key"))?
+                        .into_inner();
+                let node = Node::try_from_slice(
+                    iter.value().expect("all DB entries should have a value"),
+                )?;
+                if let Node::Leaf(leaf_node) = node {
+                    return Ok(Some((node_key, leaf_node)));
+                }
+            }
+
+            Ok(None)
+        }
+    }
+
+    /// Builds an in-memory JMT from `keys`, restores its leaves into a fresh on-disk
+    /// substore one chunk at a time, and checks that the restored root hash matches what
+    /// the in-memory tree actually committed to. This is the scenario that a naive
+    /// restore implementation gets wrong by materializing an `InternalNode` for every
+    /// single-child frontier level instead of compressing it away, which changes the
+    /// root hash for any tree with more than a trivial number of keys.
+    #[test]
+    fn restore_round_trip_matches_committed_root() {
+        let version = 0;
+        let source = MockTreeStore::default();
+        let source_tree = jmt::Sha256Jmt::new(&source);
+
+        let keys: Vec<Vec<u8>> = (0..40u32).map(|i| format!("key-{i}").into_bytes()).collect();
+        let value_set = keys
+            .iter()
+            .map(|key| (KeyHash::with::<sha2::Sha256>(key), Some(format!("value-{key:?}").into_bytes())));
+
+        let (target_root_hash, batch) = source_tree
+            .put_value_set(value_set, version)
+            .expect("failed to build source tree");
+        source
+            .write_node_batch(&batch.node_batch)
+            .expect("failed to persist source tree");
+
+        let config = Arc::new(SubstoreConfig::new("restore-round-trip-test"));
+        let (_dir, db) = open_test_substore(&config);
+
+        let mut restore = config
+            .clone()
+            .restore(db.clone(), target_root_hash, version)
+            .expect("failed to begin restore");
+
+        let mut ordered_keys = keys.clone();
+        ordered_keys.sort_by_key(|key| KeyHash::with::<sha2::Sha256>(key));
+        for key in &ordered_keys {
+            let key_hash = KeyHash::with::<sha2::Sha256>(key);
+            let (value, proof) = source_tree
+                .get_with_ics23_proof(key.clone(), version)
+                .expect("failed to build inclusion proof for source tree");
+            let value = value.expect("every restored key was committed to the source tree");
+            restore
+                .add_chunk(vec![(key_hash, value)], proof)
+                .expect("chunk should verify against the target root hash");
+        }
+
+        let restored_root_hash = restore.finalize().expect("restore should reach the target root");
+        assert_eq!(restored_root_hash, target_root_hash);
+
+        // A root-hash match alone doesn't prove the restored store is actually
+        // readable: `Node` hashes don't depend on where a node is physically stored,
+        // so a leaf persisted under the wrong on-disk address could still reproduce
+        // the right root while leaving the tree unwalkable. Walk every key back out of
+        // the restored store to confirm its nodes are reachable from the root, not
+        // just hash-equal to what should be there.
+        let restored_reader = TestTreeReader {
+            db: db.clone(),
+            config: config.clone(),
+        };
+        let restored_tree = jmt::Sha256Jmt::new(&restored_reader);
+        for key in &ordered_keys {
+            let key_hash = KeyHash::with::<sha2::Sha256>(key);
+            let restored_value = restored_tree
+                .get(key_hash, version)
+                .expect("every key should be reachable from the restored root");
+            assert_eq!(
+                restored_value,
+                Some(format!("value-{key:?}").into_bytes()),
+                "restored value for key {key:?} should match what was committed to the source tree"
+            );
+        }
+    }
+
+    /// Prunes a substore whose only write for one key predates the retention watermark,
+    /// and checks that key's `cf_jmt_values` entry survives, while a different key's
+    /// stale, truly-superseded entry does not. A naive prune that deletes every
+    /// `cf_jmt_values` entry older than the watermark (rather than only ones superseded
+    /// by a newer retained entry) makes the untouched key silently unreadable.
+    #[test]
+    fn prune_keeps_the_last_entry_for_an_untouched_key() {
+        let config = Arc::new(SubstoreConfig::new("prune-test"));
+        let (_dir, db) = open_test_substore(&config);
+        let storage = SubstoreStorage {
+            db: db.clone(),
+            config: config.clone(),
+        };
+
+        let untouched_key_hash = KeyHash::with::<sha2::Sha256>(b"untouched-since-genesis");
+        let updated_key_hash = KeyHash::with::<sha2::Sha256>(b"updated-later");
+
+        let cf_jmt_values = config.cf_jmt_values(&db);
+        let put_value = |version: jmt::Version, key_hash: KeyHash, value: &[u8]| {
+            let versioned_key = VersionedKeyHash { version, key_hash };
+            db.put_cf(
+                cf_jmt_values,
+                versioned_key.encode(),
+                Some(value.to_vec()).try_to_vec().expect("value encodes"),
+            )
+            .expect("failed to seed test value");
+        };
+
+        // Written once at genesis and never touched again.
+        put_value(0, untouched_key_hash, b"genesis-value");
+        // Written at genesis, then superseded by a later write below the watermark.
+        put_value(0, updated_key_hash, b"stale-value");
+        put_value(5, updated_key_hash, b"newer-value");
+
+        storage.prune(10).expect("prune should succeed");
+
+        let read_value = |version: jmt::Version, key_hash: KeyHash| {
+            let versioned_key = VersionedKeyHash { version, key_hash };
+            db.get_cf(cf_jmt_values, versioned_key.encode())
+                .expect("read should succeed")
+        };
+
+        assert!(
+            read_value(0, untouched_key_hash).is_some(),
+            "the only entry for a key untouched since before the watermark must survive pruning"
+        );
+        assert!(
+            read_value(0, updated_key_hash).is_none(),
+            "an entry superseded by a newer retained write should be pruned"
+        );
+        assert!(
+            read_value(5, updated_key_hash).is_some(),
+            "the newer entry superseding it must survive pruning"
+        );
+    }
+
+    /// Stages a node batch whose leaf commits to a content hash (as `commit` does once
+    /// a value crosses `value_hashing_threshold`) and checks that `cf_jmt_values` still
+    /// ends up holding the full original value, not the hash the leaf itself commits
+    /// to. `original_values` is the only thing that distinguishes staging a commit's
+    /// batch from a bare `TreeWriter::write_node_batch`, so this is the one behavior
+    /// worth pinning down: if it regressed, oversized values would become unreadable
+    /// even though the tree itself still verifies.
+    #[test]
+    fn stage_node_batch_keeps_full_values_despite_hashing_override() {
+        let config = Arc::new(SubstoreConfig::new("stage-node-batch-test"));
+        let (_dir, db) = open_test_substore(&config);
+        let storage = SubstoreStorage {
+            db: db.clone(),
+            config: config.clone(),
+        };
+
+        let key_hash = KeyHash::with::<sha2::Sha256>(b"big-value-key");
+        let full_value = vec![7u8; 64];
+        let hashed_value = content_hash(&full_value).to_vec();
+
+        // Build a one-leaf node batch the way `commit` would after substituting an
+        // oversized value for its content hash.
+        let source = MockTreeStore::default();
+        let tree = jmt::Sha256Jmt::new(&source);
+        let (_root, batch) = tree
+            .put_value_set(vec![(key_hash, Some(hashed_value))], 0)
+            .expect("failed to build node batch");
+
+        let mut original_values = std::collections::HashMap::new();
+        original_values.insert(key_hash, Some(full_value.clone()));
+
+        let mut write_batch = rocksdb::WriteBatch::default();
+        storage
+            .stage_node_batch(&mut write_batch, &batch.node_batch, &original_values)
+            .expect("staging should succeed");
+        db.write(write_batch)
+            .expect("writing the staged batch should succeed");
+
+        let cf_jmt_values = config.cf_jmt_values(&db);
+        let versioned_key = VersionedKeyHash {
+            version: 0,
+            key_hash,
+        };
+        let raw = db
+            .get_cf(cf_jmt_values, versioned_key.encode())
+            .expect("read should succeed")
+            .expect("value should have been staged");
+        let stored: Option<Vec<u8>> =
+            BorshDeserialize::try_from_slice(&raw).expect("value decodes");
+        assert_eq!(
+            stored,
+            Some(full_value),
+            "cf_jmt_values must keep the full value, not the hash the leaf committed to"
+        );
+    }
+
+    /// Seeds two value-hashing thresholds at two different versions, the way `commit`
+    /// records one marker per version, and checks each is independently retrievable by
+    /// its own version's key. This is the storage contract
+    /// `SubstoreSnapshot::value_hashing_threshold_at_version` relies on to keep
+    /// historical versions readable under whatever threshold was in effect when they
+    /// were committed, even after the configured threshold later changes.
+    #[test]
+    fn value_hashing_scheme_is_recorded_independently_per_version() {
+        let config = Arc::new(SubstoreConfig::new("value-hashing-scheme-test"));
+        let (_dir, db) = open_test_substore(&config);
+        let cf_nonverifiable = config.cf_nonverifiable(&db);
+
+        db.put_cf(
+            cf_nonverifiable,
+            value_hashing_scheme_key(0),
+            (16u64).to_be_bytes(),
+        )
+        .expect("failed to seed version 0's scheme marker");
+        db.put_cf(
+            cf_nonverifiable,
+            value_hashing_scheme_key(5),
+            (1024u64).to_be_bytes(),
+        )
+        .expect("failed to seed version 5's scheme marker");
+
+        let read_threshold = |version: jmt::Version| -> usize {
+            let bytes = db
+                .get_cf(cf_nonverifiable, value_hashing_scheme_key(version))
+                .expect("read should succeed")
+                .expect("scheme marker should be present");
+            let bytes: [u8; 8] = bytes.as_slice().try_into().expect("8 bytes");
+            u64::from_be_bytes(bytes) as usize
+        };
+
+        assert_eq!(
+            read_threshold(0),
+            16,
+            "version 0's own threshold must survive a later commit recording a different one"
+        );
+        assert_eq!(read_threshold(5), 1024);
+    }
+
+    /// Opens a database through a fresh handle, picking up whatever column families
+    /// already exist on disk — the way a real process restarting has to, since RocksDB
+    /// requires every existing column family to be named up front.
+    fn reopen_test_db(dir: &std::path::Path, opts: &rocksdb::Options) -> rocksdb::DB {
+        let cf_names =
+            rocksdb::DB::list_cf(opts, dir).unwrap_or_else(|_| vec!["default".to_string()]);
+        let descriptors: Vec<_> = cf_names
+            .into_iter()
+            .map(|name| rocksdb::ColumnFamilyDescriptor::new(name, rocksdb::Options::default()))
+            .collect();
+        rocksdb::DB::open_cf_descriptors(opts, dir, descriptors).expect("failed to reopen database")
+    }
+
+    /// Registers two substores' column families on a fresh database and checks
+    /// `discover_prefixes` finds both by name, that re-registering one is a no-op, and
+    /// that a substore with only some of its five column families (simulating a crash
+    /// partway through registration) is rejected rather than silently accepted.
+    #[test]
+    fn discover_prefixes_finds_registered_substores_and_rejects_partial_ones() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir for test");
+        let mut db_opts = rocksdb::Options::default();
+        db_opts.create_if_missing(true);
+
+        let alice = SubstoreConfig::new("alice");
+        let bob = SubstoreConfig::new("bob");
+
+        {
+            let db = reopen_test_db(dir.path(), &db_opts);
+            alice
+                .create_cf(&db, &rocksdb::Options::default())
+                .expect("failed to register alice");
+            bob.create_cf(&db, &rocksdb::Options::default())
+                .expect("failed to register bob");
+            alice
+                .create_cf(&db, &rocksdb::Options::default())
+                .expect("re-registering an already-registered substore should be a no-op");
+        }
+
+        let mut prefixes = SubstoreConfig::discover_prefixes(&db_opts, dir.path())
+            .expect("discovery should succeed");
+        prefixes.sort();
+        assert_eq!(prefixes, vec!["alice".to_string(), "bob".to_string()]);
+
+        // Simulate a crash partway through registering a third substore: only its
+        // first column family was ever created.
+        {
+            let db = reopen_test_db(dir.path(), &db_opts);
+            let carol = SubstoreConfig::new("carol");
+            db.create_cf(
+                carol.columns().next().expect("config always has columns"),
+                &rocksdb::Options::default(),
+            )
+            .expect("failed to create a single column family");
+        }
+
+        let err = SubstoreConfig::discover_prefixes(&db_opts, dir.path())
+            .expect_err("a partially-registered substore should be rejected");
+        assert!(
+            err.to_string().contains("carol"),
+            "error should name the partially-registered substore: {err}"
+        );
+    }
+}